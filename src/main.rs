@@ -1,127 +1,331 @@
 use std::collections::HashMap;
 
+/// Defines a binary arithmetic operator that promotes to `Value::Float`
+/// when either operand is a `Float`, and otherwise stays in `i32`.
 macro_rules! impl_op {
     {$name:ident, $op:tt} => {
-      fn $name(stack: &mut Vec<Value>) {
-        let rhs = stack.pop().unwrap().as_num();
-        let lhs = stack.pop().unwrap().as_num();
-        stack.push(Value::Num((lhs $op rhs) as i32));
+      fn $name(stack: &mut Vec<Value>) -> Result<(), EvalError> {
+        let rhs = stack.pop().ok_or(EvalError::StackUnderflow)?;
+        let lhs = stack.pop().ok_or(EvalError::StackUnderflow)?;
+        if matches!(rhs, Value::Float(_)) || matches!(lhs, Value::Float(_)) {
+          stack.push(Value::Float(lhs.as_f64()? $op rhs.as_f64()?));
+        } else {
+          stack.push(Value::Num(lhs.as_num()? $op rhs.as_num()?));
+        }
+        Ok(())
       }
     }
 }
 
-struct Vm<'src> {
-  stack: Vec<Value<'src>>,
-  vars: HashMap<String, Value<'src>>,
+struct Vm {
+  stack: Vec<Value>,
+  vars: Vec<HashMap<String, Value>>,
+  builtins: HashMap<&'static str, fn(&mut Vec<Value>) -> Result<(), EvalError>>,
 }
 
-impl<'src> Vm<'src> {
+impl Vm {
   fn new() -> Self {
     Self {
       stack: vec![],
-      vars: HashMap::new(),
+      vars: vec![HashMap::new()],
+      builtins: HashMap::from([
+        ("min", builtin_min as fn(&mut Vec<Value>) -> Result<(), EvalError>),
+        ("max", builtin_max as fn(&mut Vec<Value>) -> Result<(), EvalError>),
+        ("dup", builtin_dup as fn(&mut Vec<Value>) -> Result<(), EvalError>),
+        ("drop", builtin_drop as fn(&mut Vec<Value>) -> Result<(), EvalError>),
+        ("swap", builtin_swap as fn(&mut Vec<Value>) -> Result<(), EvalError>),
+        ("over", builtin_over as fn(&mut Vec<Value>) -> Result<(), EvalError>),
+        ("len", builtin_len as fn(&mut Vec<Value>) -> Result<(), EvalError>),
+        ("nth", builtin_nth as fn(&mut Vec<Value>) -> Result<(), EvalError>),
+      ]),
     }
   }
+
+  /// Looks up a name through the scope stack, innermost (function
+  /// parameters) before outermost (globals).
+  fn get_var(&self, name: &str) -> Option<&Value> {
+    self.vars.iter().rev().find_map(|scope| scope.get(name))
+  }
+
+  /// Defines `name` in the current innermost scope.
+  fn define(&mut self, name: String, value: Value) {
+    self.vars.last_mut().unwrap().insert(name, value);
+  }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-enum Value<'src> {
+/// A runtime value. `Op` and `Sym` own their text (rather than borrowing
+/// from the source line) so that `Vm` can persist across lines without
+/// tying its lifetime to whichever line produced a given value.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
   Num(i32),
-  Op(&'src str),
-  Sym(&'src str),
-  Block(Vec<Value<'src>>),
+  Float(f64),
+  Bool(bool),
+  Str(String),
+  Op(String),
+  Sym(String),
+  Block(Vec<Value>),
+  Func {
+    params: Vec<String>,
+    body: Vec<Value>,
+  },
 }
 
-impl<'src> Value<'src> {
-  fn as_num(&self) -> i32 {
+impl Value {
+  fn as_num(&self) -> Result<i32, EvalError> {
+    match self {
+      Self::Num(val) => Ok(*val),
+      other => Err(EvalError::TypeMismatch {
+        expected: "Num",
+        got: format!("{other:?}"),
+      }),
+    }
+  }
+
+  /// Coerces `Num` or `Float` to `f64`, for arithmetic that needs to
+  /// promote out of the integer tower.
+  fn as_f64(&self) -> Result<f64, EvalError> {
     match self {
-      Self::Num(val) => *val,
-      _ => panic!("Value is not a number"),
+      Self::Num(val) => Ok(*val as f64),
+      Self::Float(val) => Ok(*val),
+      other => Err(EvalError::TypeMismatch {
+        expected: "Num or Float",
+        got: format!("{other:?}"),
+      }),
     }
   }
 
-  fn to_block(self) -> Vec<Value<'src>> {
+  /// Coerces to a boolean for truthiness checks: `Bool` passes through,
+  /// `Num` is nonzero-is-true, anything else is a type error.
+  fn as_bool(&self) -> Result<bool, EvalError> {
     match self {
-      Self::Block(val) => val,
-      _ => panic!("Value is not a block"),
+      Self::Bool(val) => Ok(*val),
+      Self::Num(val) => Ok(*val != 0),
+      other => Err(EvalError::TypeMismatch {
+        expected: "Bool or Num",
+        got: format!("{other:?}"),
+      }),
     }
   }
+
+  fn to_block(self) -> Result<Vec<Value>, EvalError> {
+    match self {
+      Self::Block(val) => Ok(val),
+      other => Err(EvalError::TypeMismatch {
+        expected: "Block",
+        got: format!("{other:?}"),
+      }),
+    }
+  }
+}
+
+/// Errors that can arise while evaluating a line of source. Replaces the
+/// panics that used to unwind straight through the REPL loop in `main`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum EvalError {
+  StackUnderflow,
+  TypeMismatch { expected: &'static str, got: String },
+  UndefinedWord(String),
+  DivideByZero,
+  UnterminatedString,
+  IndexOutOfBounds { index: i32, len: usize },
 }
 
+impl std::fmt::Display for EvalError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::StackUnderflow => write!(f, "stack underflow"),
+      Self::TypeMismatch { expected, got } => {
+        write!(f, "type mismatch: expected {expected}, got {got}")
+      }
+      Self::UndefinedWord(word) => write!(f, "{word:?} is not a defined operation"),
+      Self::DivideByZero => write!(f, "divide by zero"),
+      Self::UnterminatedString => write!(f, "unterminated string literal"),
+      Self::IndexOutOfBounds { index, len } => {
+        write!(f, "index {index} out of bounds for a block of length {len}")
+      }
+    }
+  }
+}
+
+impl std::error::Error for EvalError {}
+
 fn main() {
+  let mut vm = Vm::new();
   for line in std::io::stdin().lines().flatten() {
-    parse(&line);
+    if let Err(err) = parse(&line, &mut vm) {
+      println!("error: {err}");
+      vm.stack.clear();
+    }
   }
 }
 
-fn parse<'a>(line: &'a str) -> Vec<Value> {
-  let mut vm = Vm::new();
-  let input: Vec<_> = line.split(" ").collect();
-  let mut words = &input[..];
+/// A lexical token. Unlike the raw `&str` words `split(" ")` used to
+/// produce, this survives arbitrary whitespace runs, drops `#` comments,
+/// turns `"..."` into an owned, escape-processed string, and recognizes
+/// a `/name` symbol uniformly so `parse`/`parse_block` don't each need
+/// their own copy of that rule.
+#[derive(Debug, Clone, PartialEq)]
+enum Token<'src> {
+  Word(&'src str),
+  Sym(&'src str),
+  Str(String),
+  LBrace,
+  RBrace,
+}
 
-  while let Some((&word, mut rest)) = words.split_first() {
-    if word.is_empty() {
-      break;
-    }
-    if word == "{" {
-      let value;
-      (value, rest) = parse_block(rest);
-      vm.stack.push(value);
+fn tokenize(line: &str) -> Result<Vec<Token<'_>>, EvalError> {
+  let mut tokens = vec![];
+  let mut chars = line.char_indices().peekable();
+
+  while let Some(&(start, ch)) = chars.peek() {
+    if ch.is_whitespace() {
+      chars.next();
+    } else if ch == '#' {
+      while chars.next().is_some() {}
+    } else if ch == '{' {
+      chars.next();
+      tokens.push(Token::LBrace);
+    } else if ch == '}' {
+      chars.next();
+      tokens.push(Token::RBrace);
+    } else if ch == '"' {
+      chars.next();
+      let mut value = String::new();
+      loop {
+        match chars.next() {
+          Some((_, '"')) => break,
+          Some((_, '\\')) => match chars.next() {
+            Some((_, 'n')) => value.push('\n'),
+            Some((_, '"')) => value.push('"'),
+            Some((_, '\\')) => value.push('\\'),
+            Some((_, other)) => value.push(other),
+            None => return Err(EvalError::UnterminatedString),
+          },
+          Some((_, other)) => value.push(other),
+          None => return Err(EvalError::UnterminatedString),
+        }
+      }
+      tokens.push(Token::Str(value));
     } else {
-      let code = if let Ok(num) = word.parse::<i32>() {
-        Value::Num(num)
-      } else if word.starts_with("/") {
-        Value::Sym(&word[1..])
-      } else {
-        Value::Op(word)
-      };
-      eval(code, &mut vm);
+      while let Some(&(_, c)) = chars.peek() {
+        if c.is_whitespace() || matches!(c, '{' | '}' | '"' | '#') {
+          break;
+        }
+        chars.next();
+      }
+      let end = chars.peek().map_or(line.len(), |&(idx, _)| idx);
+      let word = &line[start..end];
+      match word.strip_prefix('/').filter(|name| !name.is_empty()) {
+        Some(name) => tokens.push(Token::Sym(name)),
+        None => tokens.push(Token::Word(word)),
+      }
+    }
+  }
+
+  Ok(tokens)
+}
+
+fn parse(line: &str, vm: &mut Vm) -> Result<(), EvalError> {
+  let tokens = tokenize(line)?;
+  let mut words = &tokens[..];
+
+  while let Some((word, mut rest)) = words.split_first() {
+    match word {
+      Token::LBrace => {
+        let value;
+        (value, rest) = parse_block(rest);
+        vm.stack.push(value);
+      }
+      Token::RBrace => eval(Value::Op("}".to_string()), vm)?,
+      Token::Str(value) => vm.stack.push(Value::Str(value.clone())),
+      Token::Sym(name) => eval(Value::Sym(name.to_string()), vm)?,
+      Token::Word(word) => {
+        let code = if let Ok(num) = word.parse::<i32>() {
+          Value::Num(num)
+        } else if let Ok(num) = word.parse::<f64>() {
+          Value::Float(num)
+        } else {
+          Value::Op(word.to_string())
+        };
+        eval(code, vm)?;
+      }
     }
     words = rest;
   }
 
-  println!("stack: {stack:?}");
+  println!("stack: {:?}", vm.stack);
 
-  stack
+  Ok(())
 }
 
-fn eval<'src>(code: Value<'src>, vm: &mut Vm<'src>) {
+fn eval(code: Value, vm: &mut Vm) -> Result<(), EvalError> {
   match code {
-    Value::Op(op) => match op {
+    Value::Op(op) => match op.as_str() {
       "+" => add(&mut vm.stack),
       "-" => sub(&mut vm.stack),
       "*" => mul(&mut vm.stack),
       "/" => div(&mut vm.stack),
-      "if" => op_if(&mut vm.stack),
+      "<" => lt(&mut vm.stack),
+      ">" => gt(&mut vm.stack),
+      "<=" => le(&mut vm.stack),
+      ">=" => ge(&mut vm.stack),
+      "==" => num_eq(&mut vm.stack),
+      "!=" => num_ne(&mut vm.stack),
+      "and" => op_and(&mut vm.stack),
+      "or" => op_or(&mut vm.stack),
+      "not" => op_not(&mut vm.stack),
+      "if" => op_if(vm),
+      "while" => op_while(vm),
       "def" => op_def(vm),
+      "fn" => op_fn(vm),
       _ => {
-        let val = vm.vars.get(op).expect(&format!(
-          "{op:?} is not a defined operation"
-        ));
-        vm.stack.push(val.clone());
+        if let Some(&builtin) = vm.builtins.get(op.as_str()) {
+          return builtin(&mut vm.stack);
+        }
+        let val = vm
+          .get_var(&op)
+          .cloned()
+          .ok_or_else(|| EvalError::UndefinedWord(op.clone()))?;
+        match val {
+          Value::Func { params, body } => call_func(vm, params, body),
+          other => {
+            vm.stack.push(other);
+            Ok(())
+          }
+        }
+      }
     },
-    _ => vm.stack.push(code.clone()),
+    _ => {
+      vm.stack.push(code);
+      Ok(())
+    }
   }
 }
 
-fn parse_block<'src, 'a>(input: &'a [&'src str]) -> (Value<'src>, &'a [&'src str]) {
+fn parse_block<'a>(input: &'a [Token<'a>]) -> (Value, &'a [Token<'a>]) {
   let mut tokens = vec![];
   let mut words = input;
 
-  while let Some((&word, mut rest)) = words.split_first() {
-    if word.is_empty() {
-      break;
-    }
-    if word == "{" {
-      let value;
-      (value, rest) = parse_block(rest);
-      tokens.push(value);
-    } else if word == "}" {
-      return (Value::Block(tokens), rest);
-    } else if let Ok(value) = word.parse::<i32>() {
-      tokens.push(Value::Num(value));
-    } else {
-      tokens.push(Value::Op(word));
+  while let Some((word, mut rest)) = words.split_first() {
+    match word {
+      Token::LBrace => {
+        let value;
+        (value, rest) = parse_block(rest);
+        tokens.push(value);
+      }
+      Token::RBrace => return (Value::Block(tokens), rest),
+      Token::Str(value) => tokens.push(Value::Str(value.clone())),
+      Token::Sym(name) => tokens.push(Value::Sym(name.to_string())),
+      Token::Word(word) => {
+        if let Ok(value) = word.parse::<i32>() {
+          tokens.push(Value::Num(value));
+        } else if let Ok(value) = word.parse::<f64>() {
+          tokens.push(Value::Float(value));
+        } else {
+          tokens.push(Value::Op(word.to_string()));
+        }
+      }
     }
     words = rest;
   }
@@ -129,71 +333,400 @@ fn parse_block<'src, 'a>(input: &'a [&'src str]) -> (Value<'src>, &'a [&'src str
   (Value::Block(tokens), words)
 }
 
-fn add(stack: &mut Vec<Value>) {
-  let rhs = stack.pop().unwrap().as_num();
-  let lhs = stack.pop().unwrap().as_num();
-  stack.push(Value::Num(lhs + rhs));
+impl_op! {add, +}
+impl_op! {sub, -}
+impl_op! {mul, *}
+
+fn div(stack: &mut Vec<Value>) -> Result<(), EvalError> {
+  let rhs = stack.pop().ok_or(EvalError::StackUnderflow)?;
+  let lhs = stack.pop().ok_or(EvalError::StackUnderflow)?;
+  if matches!(rhs, Value::Float(_)) || matches!(lhs, Value::Float(_)) {
+    stack.push(Value::Float(lhs.as_f64()? / rhs.as_f64()?));
+  } else {
+    let rhs = rhs.as_num()?;
+    let lhs = lhs.as_num()?;
+    if rhs == 0 {
+      return Err(EvalError::DivideByZero);
+    }
+    stack.push(Value::Num(lhs / rhs));
+  }
+  Ok(())
+}
+
+fn lt(stack: &mut Vec<Value>) -> Result<(), EvalError> {
+  let rhs = stack.pop().ok_or(EvalError::StackUnderflow)?.as_f64()?;
+  let lhs = stack.pop().ok_or(EvalError::StackUnderflow)?.as_f64()?;
+  stack.push(Value::Bool(lhs < rhs));
+  Ok(())
+}
+
+fn gt(stack: &mut Vec<Value>) -> Result<(), EvalError> {
+  let rhs = stack.pop().ok_or(EvalError::StackUnderflow)?.as_f64()?;
+  let lhs = stack.pop().ok_or(EvalError::StackUnderflow)?.as_f64()?;
+  stack.push(Value::Bool(lhs > rhs));
+  Ok(())
+}
+
+fn le(stack: &mut Vec<Value>) -> Result<(), EvalError> {
+  let rhs = stack.pop().ok_or(EvalError::StackUnderflow)?.as_f64()?;
+  let lhs = stack.pop().ok_or(EvalError::StackUnderflow)?.as_f64()?;
+  stack.push(Value::Bool(lhs <= rhs));
+  Ok(())
+}
+
+fn ge(stack: &mut Vec<Value>) -> Result<(), EvalError> {
+  let rhs = stack.pop().ok_or(EvalError::StackUnderflow)?.as_f64()?;
+  let lhs = stack.pop().ok_or(EvalError::StackUnderflow)?.as_f64()?;
+  stack.push(Value::Bool(lhs >= rhs));
+  Ok(())
+}
+
+fn num_eq(stack: &mut Vec<Value>) -> Result<(), EvalError> {
+  let rhs = stack.pop().ok_or(EvalError::StackUnderflow)?;
+  let lhs = stack.pop().ok_or(EvalError::StackUnderflow)?;
+  stack.push(Value::Bool(lhs == rhs));
+  Ok(())
+}
+
+fn num_ne(stack: &mut Vec<Value>) -> Result<(), EvalError> {
+  let rhs = stack.pop().ok_or(EvalError::StackUnderflow)?;
+  let lhs = stack.pop().ok_or(EvalError::StackUnderflow)?;
+  stack.push(Value::Bool(lhs != rhs));
+  Ok(())
+}
+
+fn op_and(stack: &mut Vec<Value>) -> Result<(), EvalError> {
+  let rhs = stack.pop().ok_or(EvalError::StackUnderflow)?.as_bool()?;
+  let lhs = stack.pop().ok_or(EvalError::StackUnderflow)?.as_bool()?;
+  stack.push(Value::Bool(lhs && rhs));
+  Ok(())
+}
+
+fn op_or(stack: &mut Vec<Value>) -> Result<(), EvalError> {
+  let rhs = stack.pop().ok_or(EvalError::StackUnderflow)?.as_bool()?;
+  let lhs = stack.pop().ok_or(EvalError::StackUnderflow)?.as_bool()?;
+  stack.push(Value::Bool(lhs || rhs));
+  Ok(())
+}
+
+fn op_not(stack: &mut Vec<Value>) -> Result<(), EvalError> {
+  let val = stack.pop().ok_or(EvalError::StackUnderflow)?.as_bool()?;
+  stack.push(Value::Bool(!val));
+  Ok(())
+}
+
+fn builtin_min(stack: &mut Vec<Value>) -> Result<(), EvalError> {
+  let rhs = stack.pop().ok_or(EvalError::StackUnderflow)?;
+  let lhs = stack.pop().ok_or(EvalError::StackUnderflow)?;
+  stack.push(if lhs.as_f64()? <= rhs.as_f64()? { lhs } else { rhs });
+  Ok(())
+}
+
+fn builtin_max(stack: &mut Vec<Value>) -> Result<(), EvalError> {
+  let rhs = stack.pop().ok_or(EvalError::StackUnderflow)?;
+  let lhs = stack.pop().ok_or(EvalError::StackUnderflow)?;
+  stack.push(if lhs.as_f64()? >= rhs.as_f64()? { lhs } else { rhs });
+  Ok(())
 }
 
-fn sub(stack: &mut Vec<Value>) {
-  let rhs = stack.pop().unwrap().as_num();
-  let lhs = stack.pop().unwrap().as_num();
-  stack.push(Value::Num(lhs - rhs));
+fn builtin_dup(stack: &mut Vec<Value>) -> Result<(), EvalError> {
+  let top = stack.last().cloned().ok_or(EvalError::StackUnderflow)?;
+  stack.push(top);
+  Ok(())
 }
 
-fn mul(stack: &mut Vec<Value>) {
-  let rhs = stack.pop().unwrap().as_num();
-  let lhs = stack.pop().unwrap().as_num();
-  stack.push(Value::Num(lhs * rhs));
+fn builtin_drop(stack: &mut Vec<Value>) -> Result<(), EvalError> {
+  stack.pop().ok_or(EvalError::StackUnderflow)?;
+  Ok(())
 }
 
-fn div(stack: &mut Vec<Value>) {
-  let rhs = stack.pop().unwrap().as_num();
-  let lhs = stack.pop().unwrap().as_num();
-  stack.push(Value::Num(lhs / rhs));
+fn builtin_swap(stack: &mut Vec<Value>) -> Result<(), EvalError> {
+  let top = stack.pop().ok_or(EvalError::StackUnderflow)?;
+  let below = stack.pop().ok_or(EvalError::StackUnderflow)?;
+  stack.push(top);
+  stack.push(below);
+  Ok(())
 }
 
-fn op_if(stack: &mut Vec<Value>) {
-  let false_branch = stack.pop().unwrap().to_block();
-  let true_branch = stack.pop().unwrap().to_block();
-  let cond = stack.pop().unwrap().to_block();
+fn builtin_over(stack: &mut Vec<Value>) -> Result<(), EvalError> {
+  let below = stack
+    .len()
+    .checked_sub(2)
+    .and_then(|idx| stack.get(idx))
+    .cloned()
+    .ok_or(EvalError::StackUnderflow)?;
+  stack.push(below);
+  Ok(())
+}
+
+fn builtin_len(stack: &mut Vec<Value>) -> Result<(), EvalError> {
+  let block = stack.pop().ok_or(EvalError::StackUnderflow)?.to_block()?;
+  stack.push(Value::Num(block.len() as i32));
+  Ok(())
+}
+
+fn builtin_nth(stack: &mut Vec<Value>) -> Result<(), EvalError> {
+  let index = stack.pop().ok_or(EvalError::StackUnderflow)?.as_num()?;
+  let block = stack.pop().ok_or(EvalError::StackUnderflow)?.to_block()?;
+  let len = block.len();
+  let value = usize::try_from(index)
+    .ok()
+    .and_then(|idx| block.into_iter().nth(idx))
+    .ok_or(EvalError::IndexOutOfBounds { index, len })?;
+  stack.push(value);
+  Ok(())
+}
+
+fn op_if(vm: &mut Vm) -> Result<(), EvalError> {
+  let false_branch = vm.stack.pop().ok_or(EvalError::StackUnderflow)?.to_block()?;
+  let true_branch = vm.stack.pop().ok_or(EvalError::StackUnderflow)?.to_block()?;
+  let cond = vm.stack.pop().ok_or(EvalError::StackUnderflow)?.to_block()?;
 
   for code in cond {
-    eval(code, stack);
+    eval(code, vm)?;
   }
 
-  let cond_result = stack.pop().unwrap().as_num();
+  let cond_result = vm.stack.pop().ok_or(EvalError::StackUnderflow)?.as_bool()?;
 
-  if cond_result != 0 {
+  if cond_result {
     for code in true_branch {
-      eval(code, stack);
+      eval(code, vm)?;
     }
   } else {
     for code in false_branch {
-      eval(code, stack);
+      eval(code, vm)?;
+    }
+  }
+
+  Ok(())
+}
+
+/// `{ cond } { body } while` repeatedly evaluates `cond`, checks the single
+/// resulting value for truthiness, and evaluates `body` while it holds.
+fn op_while(vm: &mut Vm) -> Result<(), EvalError> {
+  let body = vm.stack.pop().ok_or(EvalError::StackUnderflow)?.to_block()?;
+  let cond = vm.stack.pop().ok_or(EvalError::StackUnderflow)?.to_block()?;
+
+  loop {
+    for code in cond.clone() {
+      eval(code, vm)?;
+    }
+
+    let cond_result = vm.stack.pop().ok_or(EvalError::StackUnderflow)?.as_bool()?;
+    if !cond_result {
+      break;
+    }
+
+    for code in body.clone() {
+      eval(code, vm)?;
     }
   }
+
+  Ok(())
+}
+
+fn op_def(vm: &mut Vm) -> Result<(), EvalError> {
+  let value = vm.stack.pop().ok_or(EvalError::StackUnderflow)?;
+  let sym = vm.stack.pop().ok_or(EvalError::StackUnderflow)?;
+
+  if let Value::Sym(name) = sym {
+    vm.define(name.to_string(), value);
+    Ok(())
+  } else {
+    Err(EvalError::TypeMismatch {
+      expected: "Sym",
+      got: format!("{sym:?}"),
+    })
+  }
+}
+
+/// `/name { params } { body } fn` defines `name` as a callable `Value::Func`.
+fn op_fn(vm: &mut Vm) -> Result<(), EvalError> {
+  let body = vm.stack.pop().ok_or(EvalError::StackUnderflow)?.to_block()?;
+  let params_block = vm.stack.pop().ok_or(EvalError::StackUnderflow)?.to_block()?;
+  let sym = vm.stack.pop().ok_or(EvalError::StackUnderflow)?;
+
+  let params = params_block
+    .into_iter()
+    .map(|value| match value {
+      Value::Op(name) => Ok(name.to_string()),
+      other => Err(EvalError::TypeMismatch {
+        expected: "Op",
+        got: format!("{other:?}"),
+      }),
+    })
+    .collect::<Result<Vec<_>, _>>()?;
+
+  if let Value::Sym(name) = sym {
+    vm.define(name.to_string(), Value::Func { params, body });
+    Ok(())
+  } else {
+    Err(EvalError::TypeMismatch {
+      expected: "Sym",
+      got: format!("{sym:?}"),
+    })
+  }
+}
+
+/// Binds `params` to the topmost `params.len()` stack values (rightmost
+/// parameter takes the topmost value), evaluates `body` in that fresh
+/// scope layered over the current one, then restores the caller's scope.
+fn call_func(vm: &mut Vm, params: Vec<String>, body: Vec<Value>) -> Result<(), EvalError> {
+  let mut scope = HashMap::new();
+  for name in params.into_iter().rev() {
+    let arg = vm.stack.pop().ok_or(EvalError::StackUnderflow)?;
+    scope.insert(name, arg);
+  }
+
+  vm.vars.push(scope);
+  let result = body.into_iter().try_for_each(|code| eval(code, vm));
+  vm.vars.pop();
+  result
 }
 
 #[cfg(test)]
 mod test {
-  use super::{parse, Value::*};
+  use super::{parse, Value::*, Vm};
 
   #[test]
   fn test_group() {
-    assert_eq!(
-      parse("1 2 + { 3 4 }"),
-      vec![Num(3), Block(vec![Num(3), Num(4)])]
-    );
+    let mut vm = Vm::new();
+    parse("1 2 + { 3 4 }", &mut vm).unwrap();
+    assert_eq!(vm.stack, vec![Num(3), Block(vec![Num(3), Num(4)])]);
   }
 
   #[test]
   fn test_if_false() {
-    assert_eq!(parse("{ 1 -1 + } { 100 } { -100 } if"), vec![Num(-100)]);
+    let mut vm = Vm::new();
+    parse("{ 1 -1 + } { 100 } { -100 } if", &mut vm).unwrap();
+    assert_eq!(vm.stack, vec![Num(-100)]);
   }
 
   #[test]
   fn test_if_true() {
-    assert_eq!(parse("{ 1 1 + } { 100 } { -100 } if"), vec![Num(100)]);
+    let mut vm = Vm::new();
+    parse("{ 1 1 + } { 100 } { -100 } if", &mut vm).unwrap();
+    assert_eq!(vm.stack, vec![Num(100)]);
+  }
+
+  #[test]
+  fn test_div_by_zero() {
+    let mut vm = Vm::new();
+    assert!(parse("1 0 /", &mut vm).is_err());
+  }
+
+  #[test]
+  fn test_undefined_word() {
+    let mut vm = Vm::new();
+    assert!(parse("nope", &mut vm).is_err());
+  }
+
+  #[test]
+  fn test_fn_call() {
+    let mut vm = Vm::new();
+    parse("/add { a b } { a b + } fn", &mut vm).unwrap();
+    parse("3 4 add", &mut vm).unwrap();
+    assert_eq!(vm.stack, vec![Num(7)]);
+  }
+
+  #[test]
+  fn test_fn_params_do_not_leak() {
+    let mut vm = Vm::new();
+    parse("/add { a b } { a b + } fn", &mut vm).unwrap();
+    parse("3 4 add", &mut vm).unwrap();
+    assert!(parse("a", &mut vm).is_err());
+  }
+
+  #[test]
+  fn test_comparison_if() {
+    let mut vm = Vm::new();
+    parse("{ 3 4 < } { 100 } { -100 } if", &mut vm).unwrap();
+    assert_eq!(vm.stack, vec![Num(100)]);
+  }
+
+  #[test]
+  fn test_and_or_not() {
+    let mut vm = Vm::new();
+    parse("1 2 < 3 4 > or", &mut vm).unwrap();
+    assert_eq!(vm.stack, vec![Bool(true)]);
+
+    let mut vm = Vm::new();
+    parse("1 2 < not", &mut vm).unwrap();
+    assert_eq!(vm.stack, vec![Bool(false)]);
+  }
+
+  #[test]
+  fn test_float_parsing_and_arithmetic() {
+    let mut vm = Vm::new();
+    parse("1.5 2.5 +", &mut vm).unwrap();
+    assert_eq!(vm.stack, vec![Float(4.0)]);
+  }
+
+  #[test]
+  fn test_mixed_num_float_promotes() {
+    let mut vm = Vm::new();
+    parse("1 2.0 +", &mut vm).unwrap();
+    assert_eq!(vm.stack, vec![Float(3.0)]);
+  }
+
+  #[test]
+  fn test_stack_builtins() {
+    let mut vm = Vm::new();
+    parse("3 4 min", &mut vm).unwrap();
+    assert_eq!(vm.stack, vec![Num(3)]);
+
+    let mut vm = Vm::new();
+    parse("3 4 max", &mut vm).unwrap();
+    assert_eq!(vm.stack, vec![Num(4)]);
+
+    let mut vm = Vm::new();
+    parse("1 dup", &mut vm).unwrap();
+    assert_eq!(vm.stack, vec![Num(1), Num(1)]);
+
+    let mut vm = Vm::new();
+    parse("1 2 swap", &mut vm).unwrap();
+    assert_eq!(vm.stack, vec![Num(2), Num(1)]);
+
+    let mut vm = Vm::new();
+    parse("1 2 over", &mut vm).unwrap();
+    assert_eq!(vm.stack, vec![Num(1), Num(2), Num(1)]);
+  }
+
+  #[test]
+  fn test_block_len_and_nth() {
+    let mut vm = Vm::new();
+    parse("{ 10 20 30 } len", &mut vm).unwrap();
+    assert_eq!(vm.stack, vec![Num(3)]);
+
+    let mut vm = Vm::new();
+    parse("{ 10 20 30 } 1 nth", &mut vm).unwrap();
+    assert_eq!(vm.stack, vec![Num(20)]);
+  }
+
+  #[test]
+  fn test_tokenizer_whitespace_and_comments() {
+    let mut vm = Vm::new();
+    parse("1    2\t+  # trailing comment", &mut vm).unwrap();
+    assert_eq!(vm.stack, vec![Num(3)]);
+  }
+
+  #[test]
+  fn test_tokenizer_string_literal() {
+    let mut vm = Vm::new();
+    parse(r#" "hello\nworld" "#, &mut vm).unwrap();
+    assert_eq!(vm.stack, vec![Str("hello\nworld".to_string())]);
+  }
+
+  #[test]
+  fn test_while_sums_to_six() {
+    let mut vm = Vm::new();
+    parse("/sum 0 def /i 0 def", &mut vm).unwrap();
+    parse(
+      "{ i 3 <= } { /sum sum i + def /i i 1 + def } while",
+      &mut vm,
+    )
+    .unwrap();
+    parse("sum", &mut vm).unwrap();
+    assert_eq!(vm.stack, vec![Num(6)]);
   }
 }